@@ -0,0 +1,229 @@
+//! ICRC-1 fungible token surface, layered on top of the existing name-keyed
+//! `TokenState` ledger so standard IC wallets and explorers can talk to this
+//! canister without a custom client. The legacy `create_account`/`send_token`
+//! API keeps working; ICRC-1 accounts are resolved onto the same balance
+//! entries via `account_key`.
+
+use candid::{CandidType, Nat, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::{stable, Account as LedgerAccount, TokenState, Transaction, DEFAULT_TOKEN_CODE};
+
+pub const TOKEN_NAME: &str = "Evon Token";
+pub const TOKEN_SYMBOL: &str = "EVN";
+pub const TOKEN_DECIMALS: u8 = 8;
+pub const DEFAULT_FEE: u64 = 10_000;
+
+const TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT_NANOS: u64 = 60 * 1_000_000_000;
+
+/// An ICRC-1 account: an owning principal plus an optional 32-byte subaccount.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<[u8; 32]>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<[u8; 32]>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(i64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// A recently accepted transfer, kept only long enough to detect resubmits
+/// within the ICRC-1 de-duplication window.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TxLogEntry {
+    from_key: String,
+    to_key: String,
+    amount: u64,
+    memo: Option<Vec<u8>>,
+    created_at_time: u64,
+    index: u64,
+}
+
+/// Maps an ICRC-1 account onto the string key used by the legacy accounts map.
+pub fn account_key(account: &Account) -> String {
+    match account.subaccount {
+        Some(sub) if sub != [0u8; 32] => {
+            format!("{}.{}", account.owner.to_text(), encode_subaccount(&sub))
+        }
+        _ => account.owner.to_text(),
+    }
+}
+
+fn encode_subaccount(sub: &[u8; 32]) -> String {
+    sub.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn metadata() -> Vec<(String, MetadataValue)> {
+    vec![
+        ("icrc1:name".to_string(), MetadataValue::Text(TOKEN_NAME.to_string())),
+        ("icrc1:symbol".to_string(), MetadataValue::Text(TOKEN_SYMBOL.to_string())),
+        ("icrc1:decimals".to_string(), MetadataValue::Nat(Nat::from(TOKEN_DECIMALS))),
+        ("icrc1:fee".to_string(), MetadataValue::Nat(Nat::from(DEFAULT_FEE))),
+    ]
+}
+
+pub fn balance_of(account: &Account) -> Nat {
+    let key = account_key(account);
+    Nat::from(stable::get_account(&key).map(|a| a.balance(DEFAULT_TOKEN_CODE)).unwrap_or(0))
+}
+
+pub fn transfer(
+    state: &mut TokenState,
+    from: &Account,
+    arg: TransferArg,
+) -> Result<Nat, TransferError> {
+    let now = ic_cdk::api::time();
+
+    if let Some(created_at_time) = arg.created_at_time {
+        if created_at_time + TX_WINDOW_NANOS < now {
+            return Err(TransferError::TooOld);
+        }
+        if created_at_time > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+            return Err(TransferError::CreatedInFuture { ledger_time: now });
+        }
+    }
+
+    if let Some(fee) = &arg.fee {
+        if *fee != Nat::from(DEFAULT_FEE) {
+            return Err(TransferError::BadFee { expected_fee: Nat::from(DEFAULT_FEE) });
+        }
+    }
+
+    if let Some(memo) = &arg.memo {
+        if memo.len() > crate::MAX_MEMO_BYTES {
+            return Err(TransferError::GenericError {
+                error_code: Nat::from(3u32),
+                message: format!("memo exceeds {} bytes", crate::MAX_MEMO_BYTES),
+            });
+        }
+    }
+
+    let amount_u64: u64 = (&arg.amount).0.clone().try_into().map_err(|_| {
+        TransferError::GenericError {
+            error_code: Nat::from(1u32),
+            message: "amount out of range".to_string(),
+        }
+    })?;
+
+    let from_key = account_key(from);
+    let to_key = account_key(&arg.to);
+
+    if let Some(created_at_time) = arg.created_at_time {
+        if let Some(existing) = state.icrc1_tx_log.iter().find(|entry| {
+            entry.from_key == from_key
+                && entry.to_key == to_key
+                && entry.amount == amount_u64
+                && entry.memo == arg.memo
+                && entry.created_at_time == created_at_time
+        }) {
+            return Err(TransferError::Duplicate { duplicate_of: Nat::from(existing.index) });
+        }
+    }
+
+    let total_debit = amount_u64.checked_add(DEFAULT_FEE).ok_or_else(|| TransferError::GenericError {
+        error_code: Nat::from(2u32),
+        message: "amount overflows with fee".to_string(),
+    })?;
+
+    let sender_balance = stable::get_account(&from_key).map(|a| a.balance(DEFAULT_TOKEN_CODE)).unwrap_or(0);
+    if sender_balance < total_debit {
+        return Err(TransferError::InsufficientFunds { balance: Nat::from(sender_balance) });
+    }
+
+    let index = state.with_checkpoint(&[from_key.as_str(), to_key.as_str()], |state| {
+        let mut sender = stable::get_account(&from_key).expect("checked above");
+        sender.set_balance(DEFAULT_TOKEN_CODE, sender.balance(DEFAULT_TOKEN_CODE) - total_debit);
+        stable::insert_account(from_key.clone(), sender);
+
+        let mut recipient = stable::get_account(&to_key).unwrap_or_else(|| LedgerAccount {
+            name: to_key.clone(),
+            owner: arg.to.owner.clone(),
+            balances: HashMap::new(),
+            accepted_tokens: HashSet::from([DEFAULT_TOKEN_CODE.to_string()]),
+        });
+        recipient.set_balance(DEFAULT_TOKEN_CODE, recipient.balance(DEFAULT_TOKEN_CODE) + amount_u64);
+        stable::insert_account(to_key.clone(), recipient);
+
+        state.token_mut(DEFAULT_TOKEN_CODE).total_supply -= DEFAULT_FEE;
+
+        let index = state.icrc1_tx_index;
+        state.icrc1_tx_index += 1;
+
+        stable::append_transaction(Transaction {
+            from: from_key.clone(),
+            to: to_key.clone(),
+            token_code: DEFAULT_TOKEN_CODE.to_string(),
+            amount: amount_u64,
+            timestamp: now,
+            memo: arg.memo.clone(),
+            encrypted_memo: None,
+        });
+
+        Ok::<u64, TransferError>(index)
+    })?;
+
+    if let Some(created_at_time) = arg.created_at_time {
+        state.icrc1_tx_log.push(TxLogEntry {
+            from_key,
+            to_key,
+            amount: amount_u64,
+            memo: arg.memo,
+            created_at_time,
+            index,
+        });
+        state.icrc1_tx_log.retain(|entry| entry.created_at_time + TX_WINDOW_NANOS >= now);
+    }
+
+    Ok(Nat::from(index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_rejects_a_memo_over_the_cap() {
+        let mut state = TokenState::default();
+        let from = Account { owner: Principal::anonymous(), subaccount: None };
+        let to = Account { owner: Principal::anonymous(), subaccount: None };
+
+        let arg = TransferArg {
+            from_subaccount: None,
+            to,
+            amount: Nat::from(1u32),
+            fee: None,
+            memo: Some(vec![0u8; crate::MAX_MEMO_BYTES + 1]),
+            created_at_time: None,
+        };
+
+        let result = transfer(&mut state, &from, arg);
+
+        assert!(matches!(result, Err(TransferError::GenericError { .. })));
+    }
+}
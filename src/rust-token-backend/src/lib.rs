@@ -1,124 +1,653 @@
-use ic_cdk_macros::{init, update, query};
-use candid::CandidType;
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, update, query};
+use candid::{CandidType, Principal};
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+mod icrc1;
+mod order_book;
+mod stable;
+
+/// The token code of the original single token this ledger started with,
+/// kept registered by default so `create_account`'s `initial_balance` and
+/// the ICRC-1/order-book surfaces (which don't yet know about other tokens)
+/// keep working unchanged.
+pub(crate) const DEFAULT_TOKEN_CODE: &str = icrc1::TOKEN_SYMBOL;
+
+/// Cap on `Transaction::memo`, matching ICRC-1's convention for a short
+/// standard-compatible tag.
+const MAX_MEMO_BYTES: usize = 32;
+/// Cap on `Transaction::encrypted_memo`. The canister only enforces this
+/// length bound; it never inspects, decrypts, or otherwise validates the
+/// ciphertext itself.
+const MAX_ENCRYPTED_MEMO_BYTES: usize = 256;
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 struct Account {
     name: String,
-    balance: u64,
+    owner: Principal,
+    /// Per-token balances, keyed by token code.
+    balances: HashMap<String, u64>,
+    /// Tokens this account has opted into receiving via `accept_token`.
+    accepted_tokens: HashSet<String>,
+}
+
+impl Account {
+    fn balance(&self, token_code: &str) -> u64 {
+        self.balances.get(token_code).copied().unwrap_or(0)
+    }
+
+    fn set_balance(&mut self, token_code: &str, amount: u64) {
+        if amount == 0 {
+            self.balances.remove(token_code);
+        } else {
+            self.balances.insert(token_code.to_string(), amount);
+        }
+    }
+}
+
+/// Authorization failures for calls that move tokens out of an account.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+enum AuthError {
+    AccountNotFound,
+    NotOwner,
+    InsufficientAllowance,
+    InsufficientBalance,
+    TokenNotAccepted,
+    BalanceOverflow,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::AccountNotFound => write!(f, "Account not found"),
+            AuthError::NotOwner => write!(f, "Caller does not own this account"),
+            AuthError::InsufficientAllowance => write!(f, "Caller has insufficient allowance"),
+            AuthError::InsufficientBalance => write!(f, "Insufficient balance"),
+            AuthError::TokenNotAccepted => write!(f, "Recipient has not accepted this token"),
+            AuthError::BalanceOverflow => write!(f, "Recipient balance would overflow"),
+        }
+    }
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 struct Transaction {
     from: String,
     to: String,
+    token_code: String,
     amount: u64,
     timestamp: u64,
+    /// Short clear-text note (e.g. an invoice id), capped at `MAX_MEMO_BYTES`.
+    memo: Option<Vec<u8>>,
+    /// Opaque end-to-end encrypted note (ciphertext plus nonce, in whatever
+    /// format the client agreed on out of band). The canister stores this
+    /// blob as-is; key management is entirely the client's concern.
+    encrypted_memo: Option<Vec<u8>>,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone)]
-struct TokenState {
-    accounts: HashMap<String, Account>,
+/// Check the caller-supplied memo fields against their length bounds
+/// without looking at the encrypted memo's contents.
+fn validate_memo(memo: &Option<Vec<u8>>, encrypted_memo: &Option<Vec<u8>>) -> Result<(), String> {
+    if let Some(memo) = memo {
+        if memo.len() > MAX_MEMO_BYTES {
+            return Err(format!("memo exceeds {} bytes", MAX_MEMO_BYTES));
+        }
+    }
+    if let Some(encrypted_memo) = encrypted_memo {
+        if encrypted_memo.len() > MAX_ENCRYPTED_MEMO_BYTES {
+            return Err(format!("encrypted_memo exceeds {} bytes", MAX_ENCRYPTED_MEMO_BYTES));
+        }
+    }
+    Ok(())
+}
+
+/// A registered token's metadata and running total supply.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+struct TokenInfo {
+    symbol: String,
+    decimals: u8,
     total_supply: u64,
-    transactions: Vec<Transaction>,
+}
+
+/// Heap-resident ledger bookkeeping. Accounts and the transaction log live
+/// in stable memory directly (see `stable`) so they can grow past what
+/// would fit in a single upgrade snapshot; only the fields below need to
+/// be carried across an upgrade by hand.
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+struct TokenState {
+    /// Registered tokens, keyed by token code.
+    tokens: HashMap<String, TokenInfo>,
+    icrc1_tx_index: u64,
+    icrc1_tx_log: Vec<icrc1::TxLogEntry>,
+    /// Spend limits an owner has granted to a spender for a token, keyed by
+    /// (owner, spender, token_code).
+    allowances: HashMap<(Principal, Principal, String), u64>,
+    order_book: order_book::OrderBookState,
+}
+
+impl TokenState {
+    /// The registered info for `code`, registering it with empty defaults
+    /// the first time it's touched (used for the always-present default
+    /// token, which `init` seeds explicitly).
+    fn token_mut(&mut self, code: &str) -> &mut TokenInfo {
+        self.tokens.entry(code.to_string()).or_insert_with(|| TokenInfo {
+            symbol: code.to_string(),
+            decimals: icrc1::TOKEN_DECIMALS,
+            total_supply: 0,
+        })
+    }
+}
+
+/// A snapshot of the account entries, token registry and allowances touched
+/// by a mutation, taken so the mutation can be undone without leaving the
+/// ledger in a partial state if it fails part way through.
+struct Checkpoint {
+    accounts: HashMap<String, Option<Account>>,
+    tokens: HashMap<String, TokenInfo>,
+    allowances: HashMap<(Principal, Principal, String), u64>,
+}
+
+impl TokenState {
+    /// Snapshot `keys` as they stand right now.
+    fn checkpoint(&self, keys: &[&str]) -> Checkpoint {
+        Checkpoint {
+            accounts: keys
+                .iter()
+                .map(|key| (key.to_string(), stable::get_account(key)))
+                .collect(),
+            tokens: self.tokens.clone(),
+            allowances: self.allowances.clone(),
+        }
+    }
+
+    /// Discard a checkpoint once its mutation has succeeded.
+    fn commit(&mut self, _checkpoint: Checkpoint) {}
+
+    /// Restore the account entries, token registry and allowances captured
+    /// in `checkpoint`.
+    fn revert(&mut self, checkpoint: Checkpoint) {
+        for (key, saved) in checkpoint.accounts {
+            match saved {
+                Some(account) => stable::insert_account(key, account),
+                None => stable::remove_account(&key),
+            }
+        }
+        self.tokens = checkpoint.tokens;
+        self.allowances = checkpoint.allowances;
+    }
+
+    /// Run `f` under a checkpoint over `keys`, automatically rolling back the
+    /// touched accounts if it returns `Err`.
+    fn with_checkpoint<T, E>(
+        &mut self,
+        keys: &[&str],
+        f: impl FnOnce(&mut TokenState) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let checkpoint = self.checkpoint(keys);
+        match f(self) {
+            Ok(value) => {
+                self.commit(checkpoint);
+                Ok(value)
+            }
+            Err(err) => {
+                self.revert(checkpoint);
+                Err(err)
+            }
+        }
+    }
 }
 
 thread_local! {
-    static TOKEN_STATE: std::cell::RefCell<TokenState> = std::cell::RefCell::new(TokenState {
-        accounts: HashMap::new(),
-        total_supply: 0,
-        transactions: Vec::new(),
-    });
+    static TOKEN_STATE: std::cell::RefCell<TokenState> = std::cell::RefCell::new(TokenState::default());
 }
 
 #[init]
 fn init() {
     TOKEN_STATE.with(|state| {
         let mut token_state = state.borrow_mut();
-        token_state.total_supply = 0;
+        token_state.tokens.insert(
+            DEFAULT_TOKEN_CODE.to_string(),
+            TokenInfo {
+                symbol: icrc1::TOKEN_SYMBOL.to_string(),
+                decimals: icrc1::TOKEN_DECIMALS,
+                total_supply: 0,
+            },
+        );
+    });
+    stable::rebuild_index();
+}
+
+/// Stable structures backing `accounts`/`transactions` persist on their own;
+/// only the remaining heap fields on `TokenState` need to be carried across
+/// the upgrade by hand.
+#[pre_upgrade]
+fn pre_upgrade() {
+    TOKEN_STATE.with(|state| {
+        let bytes = candid::encode_one(&*state.borrow()).expect("failed to encode heap snapshot");
+        stable::save_heap_snapshot(&bytes);
     });
 }
 
+#[post_upgrade]
+fn post_upgrade() {
+    let bytes = stable::load_heap_snapshot();
+    let restored: TokenState = if bytes.is_empty() {
+        TokenState::default()
+    } else {
+        candid::decode_one(&bytes).expect("failed to decode heap snapshot")
+    };
+
+    TOKEN_STATE.with(|state| {
+        *state.borrow_mut() = restored;
+    });
+
+    stable::rebuild_index();
+}
+
 #[update]
 fn create_account(name: String, initial_balance: u64) -> Result<String, String> {
     TOKEN_STATE.with(|state| {
         let mut token_state = state.borrow_mut();
-        
-        if token_state.accounts.contains_key(&name) {
+
+        if stable::contains_account(&name) {
             return Err("Account already exists".to_string());
         }
-        
-        let account = Account {
+
+        let mut account = Account {
             name: name.clone(),
-            balance: initial_balance,
+            owner: ic_cdk::api::caller(),
+            balances: HashMap::new(),
+            accepted_tokens: HashSet::from([DEFAULT_TOKEN_CODE.to_string()]),
         };
-        
-        token_state.accounts.insert(name.clone(), account);
-        token_state.total_supply += initial_balance;
-        
+        account.set_balance(DEFAULT_TOKEN_CODE, initial_balance);
+
+        stable::insert_account(name.clone(), account);
+        token_state.token_mut(DEFAULT_TOKEN_CODE).total_supply += initial_balance;
+
         Ok(format!("Account created for {} with balance {}", name, initial_balance))
     })
 }
 
+/// Register a new token type. Existing accounts don't receive a balance of
+/// it until they opt in via `accept_token`.
 #[update]
-fn send_token(from: String, to: String, amount: u64) -> Result<String, String> {
+fn register_token(code: String, symbol: String, decimals: u8) -> Result<String, String> {
     TOKEN_STATE.with(|state| {
         let mut token_state = state.borrow_mut();
-        
-        let sender = token_state.accounts.get_mut(&from)
-            .ok_or_else(|| "Sender account not found".to_string())?;
-        
-        if sender.balance < amount {
-            return Err("Insufficient balance".to_string());
-        }
-        
-        sender.balance -= amount;
-        
-        let recipient = token_state.accounts.get_mut(&to)
-            .ok_or_else(|| "Recipient account not found".to_string())?;
-        recipient.balance += amount;
-        
-        let transaction = Transaction {
-            from: from.clone(),
-            to: to.clone(),
-            amount,
-            timestamp: ic_cdk::api::time(),
-        };
-        
-        token_state.transactions.push(transaction);
-        
-        Ok(format!("Sent {} tokens from {} to {}", amount, from, to))
+
+        if token_state.tokens.contains_key(&code) {
+            return Err("Token already registered".to_string());
+        }
+
+        token_state.tokens.insert(code.clone(), TokenInfo { symbol, decimals, total_supply: 0 });
+
+        Ok(format!("Registered token {}", code))
     })
 }
 
-#[query]
-fn get_balance(name: String) -> Result<u64, String> {
+/// Mint new supply of a registered token directly into `name`'s balance.
+/// Restricted to a canister controller so token supply can't be inflated by
+/// arbitrary callers.
+#[update]
+fn mint_token(name: String, code: String, amount: u64) -> Result<String, String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::api::caller()) {
+        return Err("Only a controller can mint tokens".to_string());
+    }
+
+    TOKEN_STATE.with(|state| {
+        let mut token_state = state.borrow_mut();
+
+        if !token_state.tokens.contains_key(&code) {
+            return Err("Token not registered".to_string());
+        }
+
+        let mut account = stable::get_account(&name).ok_or_else(|| AuthError::AccountNotFound.to_string())?;
+
+        if !account.accepted_tokens.contains(&code) {
+            return Err(AuthError::TokenNotAccepted.to_string());
+        }
+
+        let new_balance = account
+            .balance(&code)
+            .checked_add(amount)
+            .ok_or_else(|| "Minted balance would overflow".to_string())?;
+        account.set_balance(&code, new_balance);
+        stable::insert_account(name.clone(), account);
+
+        token_state.token_mut(&code).total_supply += amount;
+
+        Ok(format!("Minted {} {} to {}", amount, code, name))
+    })
+}
+
+/// Opt `name` into holding and receiving `code`. Accounts can't receive a
+/// token's balance until they've accepted it.
+#[update]
+fn accept_token(name: String, code: String) -> Result<String, String> {
     TOKEN_STATE.with(|state| {
-        let token_state = state.borrow();
-        token_state.accounts.get(&name)
-            .map(|account| account.balance)
-            .ok_or_else(|| "Account not found".to_string())
+        if !state.borrow().tokens.contains_key(&code) {
+            return Err("Token not registered".to_string());
+        }
+
+        let mut account = stable::get_account(&name).ok_or_else(|| AuthError::AccountNotFound.to_string())?;
+
+        if account.owner != ic_cdk::api::caller() {
+            return Err(AuthError::NotOwner.to_string());
+        }
+
+        account.accepted_tokens.insert(code.clone());
+        stable::insert_account(name.clone(), account);
+
+        Ok(format!("{} now accepts {}", name, code))
     })
 }
 
+#[update]
+fn send_token(
+    from: String,
+    to: String,
+    token_code: String,
+    amount: u64,
+    memo: Option<Vec<u8>>,
+    encrypted_memo: Option<Vec<u8>>,
+) -> Result<String, String> {
+    validate_memo(&memo, &encrypted_memo)?;
+
+    TOKEN_STATE.with(|state| {
+        let mut token_state = state.borrow_mut();
+
+        // Validate both accounts and sufficiency up front so a missing
+        // account is rejected before the checkpoint below ever runs; it
+        // still has to unwind a recipient-balance overflow, which can only
+        // be detected once the credit is actually computed.
+        let sender = stable::get_account(&from).ok_or_else(|| AuthError::AccountNotFound.to_string())?;
+
+        if sender.owner != ic_cdk::api::caller() {
+            return Err(AuthError::NotOwner.to_string());
+        }
+
+        let recipient = stable::get_account(&to).ok_or_else(|| "Recipient account not found".to_string())?;
+
+        if !recipient.accepted_tokens.contains(&token_code) {
+            return Err(AuthError::TokenNotAccepted.to_string());
+        }
+
+        if sender.balance(&token_code) < amount {
+            return Err(AuthError::InsufficientBalance.to_string());
+        }
+
+        token_state.with_checkpoint(&[from.as_str(), to.as_str()], |_state| {
+            let mut sender = stable::get_account(&from).unwrap();
+            sender.set_balance(&token_code, sender.balance(&token_code) - amount);
+            stable::insert_account(from.clone(), sender);
+
+            let mut recipient = stable::get_account(&to).unwrap();
+            let new_recipient_balance = recipient
+                .balance(&token_code)
+                .checked_add(amount)
+                .ok_or_else(|| "Recipient balance would overflow".to_string())?;
+            recipient.set_balance(&token_code, new_recipient_balance);
+            stable::insert_account(to.clone(), recipient);
+
+            stable::append_transaction(Transaction {
+                from: from.clone(),
+                to: to.clone(),
+                token_code: token_code.clone(),
+                amount,
+                timestamp: ic_cdk::api::time(),
+                memo: memo.clone(),
+                encrypted_memo: encrypted_memo.clone(),
+            });
+
+            Ok(())
+        })?;
+
+        Ok(format!("Sent {} {} from {} to {}", amount, token_code, from, to))
+    })
+}
+
+/// Grant `spender` the right to move up to `amount` of `token_code` out of
+/// any account the caller owns.
+#[update]
+fn approve(spender: Principal, token_code: String, amount: u64) -> Result<String, AuthError> {
+    let owner = ic_cdk::api::caller();
+    TOKEN_STATE.with(|state| {
+        state.borrow_mut().allowances.insert((owner.clone(), spender.clone(), token_code.clone()), amount);
+    });
+    Ok(format!("Approved {} to spend up to {} {} for {}", spender, amount, token_code, owner))
+}
+
+/// Move tokens out of `from` on behalf of its owner, within the allowance
+/// the caller was previously granted via `approve`.
+#[update]
+fn transfer_from(from: String, to: String, token_code: String, amount: u64) -> Result<String, AuthError> {
+    TOKEN_STATE.with(|state| {
+        let mut token_state = state.borrow_mut();
+        let spender = ic_cdk::api::caller();
+
+        let owner = stable::get_account(&from).ok_or(AuthError::AccountNotFound)?.owner;
+
+        let recipient = stable::get_account(&to).ok_or(AuthError::AccountNotFound)?;
+        if !recipient.accepted_tokens.contains(&token_code) {
+            return Err(AuthError::TokenNotAccepted);
+        }
+
+        let allowance = token_state
+            .allowances
+            .get(&(owner.clone(), spender.clone(), token_code.clone()))
+            .copied()
+            .unwrap_or(0);
+        if allowance < amount {
+            return Err(AuthError::InsufficientAllowance);
+        }
+
+        let sender_balance = stable::get_account(&from).unwrap().balance(&token_code);
+        if sender_balance < amount {
+            return Err(AuthError::InsufficientBalance);
+        }
+
+        token_state.with_checkpoint(&[from.as_str(), to.as_str()], |state| {
+            let mut sender = stable::get_account(&from).unwrap();
+            sender.set_balance(&token_code, sender.balance(&token_code) - amount);
+            stable::insert_account(from.clone(), sender);
+
+            let mut recipient = stable::get_account(&to).unwrap();
+            let new_recipient_balance = recipient
+                .balance(&token_code)
+                .checked_add(amount)
+                .ok_or(AuthError::BalanceOverflow)?;
+            recipient.set_balance(&token_code, new_recipient_balance);
+            stable::insert_account(to.clone(), recipient);
+
+            state.allowances.entry((owner, spender, token_code.clone())).and_modify(|a| *a -= amount);
+
+            stable::append_transaction(Transaction {
+                from: from.clone(),
+                to: to.clone(),
+                token_code: token_code.clone(),
+                amount,
+                timestamp: ic_cdk::api::time(),
+                memo: None,
+                encrypted_memo: None,
+            });
+
+            Ok::<(), AuthError>(())
+        })?;
+
+        Ok(format!("Sent {} {} from {} to {} via allowance", amount, token_code, from, to))
+    })
+}
+
+#[query]
+fn get_balance(name: String, token_code: String) -> Result<u64, String> {
+    stable::get_account(&name)
+        .map(|account| account.balance(&token_code))
+        .ok_or_else(|| "Account not found".to_string())
+}
+
+/// Every non-zero balance held by `name`, keyed by token code.
 #[query]
-fn get_total_supply() -> u64 {
+fn list_balances(name: String) -> Result<Vec<(String, u64)>, String> {
+    let account = stable::get_account(&name).ok_or_else(|| "Account not found".to_string())?;
+    let mut balances: Vec<(String, u64)> =
+        account.balances.into_iter().filter(|(_, amount)| *amount > 0).collect();
+    balances.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(balances)
+}
+
+#[query]
+fn get_total_supply(token_code: String) -> Result<u64, String> {
     TOKEN_STATE.with(|state| {
-        state.borrow().total_supply
+        state
+            .borrow()
+            .tokens
+            .get(&token_code)
+            .map(|info| info.total_supply)
+            .ok_or_else(|| "Token not registered".to_string())
     })
 }
 
 #[query]
 fn get_transaction_history(name: String) -> Vec<Transaction> {
+    stable::transactions_for(&name)
+}
+
+#[query]
+fn icrc1_name() -> String {
+    icrc1::TOKEN_NAME.to_string()
+}
+
+#[query]
+fn icrc1_symbol() -> String {
+    icrc1::TOKEN_SYMBOL.to_string()
+}
+
+#[query]
+fn icrc1_decimals() -> u8 {
+    icrc1::TOKEN_DECIMALS
+}
+
+#[query]
+fn icrc1_fee() -> candid::Nat {
+    candid::Nat::from(icrc1::DEFAULT_FEE)
+}
+
+#[query]
+fn icrc1_total_supply() -> candid::Nat {
+    TOKEN_STATE.with(|state| {
+        let supply = state.borrow().tokens.get(DEFAULT_TOKEN_CODE).map(|info| info.total_supply).unwrap_or(0);
+        candid::Nat::from(supply)
+    })
+}
+
+#[query]
+fn icrc1_metadata() -> Vec<(String, icrc1::MetadataValue)> {
+    icrc1::metadata()
+}
+
+#[query]
+fn icrc1_balance_of(account: icrc1::Account) -> candid::Nat {
+    icrc1::balance_of(&account)
+}
+
+#[update]
+fn icrc1_transfer(arg: icrc1::TransferArg) -> Result<candid::Nat, icrc1::TransferError> {
+    let from = icrc1::Account {
+        owner: ic_cdk::api::caller(),
+        subaccount: arg.from_subaccount,
+    };
+    TOKEN_STATE.with(|state| icrc1::transfer(&mut state.borrow_mut(), &from, arg))
+}
+
+/// Place a limit order for the caller's default ICRC-1 account, matching it
+/// against the resting book immediately.
+#[update]
+fn place_order(side: order_book::Side, price: u64, amount: u64) -> Result<u64, String> {
+    let owner = icrc1::account_key(&icrc1::Account {
+        owner: ic_cdk::api::caller(),
+        subaccount: None,
+    });
     TOKEN_STATE.with(|state| {
-        let token_state = state.borrow();
-        token_state.transactions.iter()
-            .filter(|tx| tx.from == name || tx.to == name)
-            .cloned()
-            .collect()
+        order_book::place_order(&mut state.borrow_mut().order_book, owner, side, price, amount)
     })
 }
 
+/// Cancel a resting order placed from the caller's default ICRC-1 account,
+/// refunding whatever of its escrow hasn't been filled yet.
+#[update]
+fn cancel_order(id: u64) -> Result<String, String> {
+    let owner = icrc1::account_key(&icrc1::Account {
+        owner: ic_cdk::api::caller(),
+        subaccount: None,
+    });
+    TOKEN_STATE.with(|state| order_book::cancel_order(&mut state.borrow_mut().order_book, &owner, id))
+}
+
+#[query]
+fn get_order_book() -> order_book::OrderBookView {
+    TOKEN_STATE.with(|state| state.borrow().order_book.view())
+}
+
 // Export the Candid interface
-ic_cdk::export_candid!();
\ No newline at end of file
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup_account(name: &str, token_code: &str, balance: u64) {
+        stable::insert_account(
+            name.to_string(),
+            Account {
+                name: name.to_string(),
+                owner: ic_cdk::id(),
+                balances: HashMap::from([(token_code.to_string(), balance)]),
+                accepted_tokens: HashSet::from([token_code.to_string()]),
+            },
+        );
+    }
+
+    fn balance_of(name: &str, token_code: &str) -> u64 {
+        stable::get_account(name).unwrap().balance(token_code)
+    }
+
+    /// Mirrors `send_token`'s mutation closure: debit the sender first, then
+    /// fallibly credit the recipient via `checked_add`. An overflowing
+    /// credit must leave the sender exactly as it was before the mutation
+    /// started, proving `with_checkpoint` actually rolls back a failure
+    /// that happens after some of its state has already been written.
+    #[test]
+    fn with_checkpoint_restores_the_sender_when_the_credit_overflows() {
+        let token_code = "OVERFLOW";
+        setup_account("sender", token_code, 100);
+        setup_account("recipient", token_code, u64::MAX);
+
+        let mut state = TokenState::default();
+        state.allowances.insert(
+            (Principal::anonymous(), Principal::anonymous(), token_code.to_string()),
+            42,
+        );
+        let allowances_before = state.allowances.clone();
+
+        let result = state.with_checkpoint(&["sender", "recipient"], |_state| {
+            let mut sender = stable::get_account("sender").unwrap();
+            sender.set_balance(token_code, sender.balance(token_code) - 100);
+            stable::insert_account("sender".to_string(), sender);
+
+            let mut recipient = stable::get_account("recipient").unwrap();
+            let new_balance = recipient
+                .balance(token_code)
+                .checked_add(100)
+                .ok_or_else(|| "Recipient balance would overflow".to_string())?;
+            recipient.set_balance(token_code, new_balance);
+            stable::insert_account("recipient".to_string(), recipient);
+
+            Ok::<(), String>(())
+        });
+
+        assert!(result.is_err());
+        assert_eq!(balance_of("sender", token_code), 100);
+        assert_eq!(balance_of("recipient", token_code), u64::MAX);
+        assert_eq!(state.allowances, allowances_before);
+    }
+}
@@ -0,0 +1,305 @@
+//! A minimal on-chain limit order book and matching engine layered on top
+//! of the existing ledger. Orders trade the ledger's default token
+//! (`DEFAULT_TOKEN_CODE`) against itself: a sell order escrows the `amount`
+//! it is offering, a buy order escrows `amount * price` as payment. Matches
+//! settle by moving tokens out of a canister-owned escrow account with the
+//! same account reads, writes and transaction records `send_token` makes,
+//! so every fill shows up in transaction history. These moves are not run
+//! under `TokenState::with_checkpoint`.
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::{stable, Transaction, DEFAULT_TOKEN_CODE};
+
+/// Reserved account name that custodies escrowed order funds.
+pub const ESCROW_ACCOUNT: &str = "order_book_escrow";
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Order {
+    pub id: u64,
+    pub side: Side,
+    pub price: u64,
+    pub amount: u64,
+    pub remaining: u64,
+    pub owner: String,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+pub struct OrderBookState {
+    next_order_id: u64,
+    /// Sorted descending by price (best bid first).
+    bids: Vec<Order>,
+    /// Sorted ascending by price (best ask first).
+    asks: Vec<Order>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct OrderBookView {
+    pub bids: Vec<Order>,
+    pub asks: Vec<Order>,
+}
+
+impl OrderBookState {
+    pub fn view(&self) -> OrderBookView {
+        OrderBookView {
+            bids: self.bids.clone(),
+            asks: self.asks.clone(),
+        }
+    }
+
+    fn insert_sorted(&mut self, order: Order) {
+        match order.side {
+            Side::Buy => {
+                let pos = self.bids.partition_point(|o| o.price > order.price);
+                self.bids.insert(pos, order);
+            }
+            Side::Sell => {
+                let pos = self.asks.partition_point(|o| o.price < order.price);
+                self.asks.insert(pos, order);
+            }
+        }
+    }
+}
+
+/// Tokens a maker must put up to place this order.
+fn escrow_cost(side: Side, price: u64, amount: u64) -> Option<u64> {
+    match side {
+        Side::Sell => Some(amount),
+        Side::Buy => amount.checked_mul(price),
+    }
+}
+
+fn ensure_escrow_account() {
+    if !stable::contains_account(ESCROW_ACCOUNT) {
+        stable::insert_account(
+            ESCROW_ACCOUNT.to_string(),
+            crate::Account {
+                name: ESCROW_ACCOUNT.to_string(),
+                owner: ic_cdk::id(),
+                balances: HashMap::new(),
+                accepted_tokens: HashSet::from([DEFAULT_TOKEN_CODE.to_string()]),
+            },
+        );
+    }
+}
+
+/// Move tokens between two existing accounts and record the transfer,
+/// mirroring the bookkeeping `send_token` does.
+fn move_tokens(from: &str, to: &str, amount: u64) {
+    if amount == 0 {
+        return;
+    }
+
+    let mut sender = stable::get_account(from).expect("escrowed account must exist");
+    sender.set_balance(DEFAULT_TOKEN_CODE, sender.balance(DEFAULT_TOKEN_CODE) - amount);
+    stable::insert_account(from.to_string(), sender);
+
+    let mut recipient = stable::get_account(to).expect("escrowed account must exist");
+    recipient.set_balance(DEFAULT_TOKEN_CODE, recipient.balance(DEFAULT_TOKEN_CODE) + amount);
+    stable::insert_account(to.to_string(), recipient);
+
+    stable::append_transaction(Transaction {
+        from: from.to_string(),
+        to: to.to_string(),
+        token_code: DEFAULT_TOKEN_CODE.to_string(),
+        amount,
+        timestamp: ic_cdk::api::time(),
+        memo: None,
+        encrypted_memo: None,
+    });
+}
+
+fn settle(buyer: &str, seller: &str, base_amount: u64, quote_amount: u64) {
+    move_tokens(ESCROW_ACCOUNT, buyer, base_amount);
+    move_tokens(ESCROW_ACCOUNT, seller, quote_amount);
+}
+
+/// Match `incoming` against the resting book on the opposite side,
+/// executing fills at each resting order's price until it no longer
+/// crosses or is fully filled.
+fn match_incoming(state: &mut OrderBookState, incoming: &mut Order) {
+    loop {
+        if incoming.remaining == 0 {
+            break;
+        }
+
+        let crosses = match incoming.side {
+            Side::Buy => state.asks.first().map(|ask| ask.price <= incoming.price).unwrap_or(false),
+            Side::Sell => state.bids.first().map(|bid| bid.price >= incoming.price).unwrap_or(false),
+        };
+        if !crosses {
+            break;
+        }
+
+        let resting = match incoming.side {
+            Side::Buy => state.asks.first_mut().unwrap(),
+            Side::Sell => state.bids.first_mut().unwrap(),
+        };
+
+        let fill = incoming.remaining.min(resting.remaining);
+        let trade_price = resting.price;
+        let (buyer, seller) = match incoming.side {
+            Side::Buy => (incoming.owner.clone(), resting.owner.clone()),
+            Side::Sell => (resting.owner.clone(), incoming.owner.clone()),
+        };
+
+        incoming.remaining -= fill;
+        resting.remaining -= fill;
+        let resting_filled = resting.remaining == 0;
+
+        settle(&buyer, &seller, fill, fill.saturating_mul(trade_price));
+
+        // A taker buy escrowed `fill * incoming.price` but the trade executes
+        // at the (lower-or-equal) resting ask price, so refund the
+        // difference rather than leaving it stranded in escrow.
+        if incoming.side == Side::Buy && incoming.price > trade_price {
+            let improvement = fill.saturating_mul(incoming.price - trade_price);
+            move_tokens(ESCROW_ACCOUNT, &incoming.owner, improvement);
+        }
+
+        if resting_filled {
+            match incoming.side {
+                Side::Buy => {
+                    state.asks.remove(0);
+                }
+                Side::Sell => {
+                    state.bids.remove(0);
+                }
+            }
+        }
+    }
+}
+
+/// Place a limit order for `owner`, matching it against the resting book
+/// and letting any unfilled remainder rest.
+pub fn place_order(
+    state: &mut OrderBookState,
+    owner: String,
+    side: Side,
+    price: u64,
+    amount: u64,
+) -> Result<u64, String> {
+    if price == 0 || amount == 0 {
+        return Err("Price and amount must be greater than zero".to_string());
+    }
+
+    let escrow_amount =
+        escrow_cost(side, price, amount).ok_or_else(|| "Order cost overflows a u64".to_string())?;
+
+    ensure_escrow_account();
+
+    let maker_balance = stable::get_account(&owner).map(|a| a.balance(DEFAULT_TOKEN_CODE)).unwrap_or(0);
+    if maker_balance < escrow_amount {
+        return Err("Insufficient balance to place order".to_string());
+    }
+
+    move_tokens(&owner, ESCROW_ACCOUNT, escrow_amount);
+
+    let id = state.next_order_id;
+    state.next_order_id += 1;
+
+    let mut incoming = Order {
+        id,
+        side,
+        price,
+        amount,
+        remaining: amount,
+        owner,
+    };
+
+    match_incoming(state, &mut incoming);
+
+    if incoming.remaining > 0 {
+        state.insert_sorted(incoming);
+    }
+
+    Ok(id)
+}
+
+/// Cancel a resting order owned by `caller_owner`, refunding whatever of
+/// its escrow hasn't been filled yet.
+pub fn cancel_order(state: &mut OrderBookState, caller_owner: &str, id: u64) -> Result<String, String> {
+    if let Some(pos) = state.bids.iter().position(|order| order.id == id) {
+        if state.bids[pos].owner != caller_owner {
+            return Err("Caller does not own this order".to_string());
+        }
+        let order = state.bids.remove(pos);
+        move_tokens(ESCROW_ACCOUNT, &order.owner, order.remaining.saturating_mul(order.price));
+        return Ok(format!("Cancelled order {}", id));
+    }
+
+    if let Some(pos) = state.asks.iter().position(|order| order.id == id) {
+        if state.asks[pos].owner != caller_owner {
+            return Err("Caller does not own this order".to_string());
+        }
+        let order = state.asks.remove(pos);
+        move_tokens(ESCROW_ACCOUNT, &order.owner, order.remaining);
+        return Ok(format!("Cancelled order {}", id));
+    }
+
+    Err("Order not found".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup_account(name: &str, balance: u64) {
+        stable::insert_account(
+            name.to_string(),
+            crate::Account {
+                name: name.to_string(),
+                owner: ic_cdk::id(),
+                balances: HashMap::from([(DEFAULT_TOKEN_CODE.to_string(), balance)]),
+                accepted_tokens: HashSet::from([DEFAULT_TOKEN_CODE.to_string()]),
+            },
+        );
+    }
+
+    fn balance_of(name: &str) -> u64 {
+        stable::get_account(name).unwrap().balance(DEFAULT_TOKEN_CODE)
+    }
+
+    #[test]
+    fn taker_buy_is_refunded_the_price_improvement() {
+        setup_account("seller", 10);
+        setup_account("buyer", 1_000);
+
+        let mut state = OrderBookState::default();
+        place_order(&mut state, "seller".to_string(), Side::Sell, 90, 10).unwrap();
+        place_order(&mut state, "buyer".to_string(), Side::Buy, 100, 10).unwrap();
+
+        // Filled at the resting ask's price (90), not the buyer's limit
+        // price (100): the 100-token improvement must come back to the
+        // buyer rather than being stranded in escrow, on top of the 10
+        // filled tokens.
+        assert_eq!(balance_of("buyer"), 110);
+        assert_eq!(balance_of("seller"), 900);
+        assert_eq!(balance_of(ESCROW_ACCOUNT), 0);
+    }
+
+    #[test]
+    fn resting_buy_pays_its_own_escrowed_price() {
+        setup_account("buyer", 1_000);
+        setup_account("seller", 10);
+
+        let mut state = OrderBookState::default();
+        place_order(&mut state, "buyer".to_string(), Side::Buy, 100, 10).unwrap();
+        place_order(&mut state, "seller".to_string(), Side::Sell, 90, 10).unwrap();
+
+        // The resting buy only ever escrowed at its own price, so no
+        // improvement is owed when a seller crosses it: the buyer ends with
+        // just the 10 filled tokens.
+        assert_eq!(balance_of("buyer"), 10);
+        assert_eq!(balance_of("seller"), 1_000);
+        assert_eq!(balance_of(ESCROW_ACCOUNT), 0);
+    }
+}
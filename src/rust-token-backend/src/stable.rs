@@ -0,0 +1,175 @@
+//! Stable-memory backing for the parts of the ledger that can outgrow a
+//! heap snapshot: the accounts map and the append-only transaction log.
+//! Both live directly in stable memory via `ic_stable_structures` so they
+//! survive an upgrade without going through `pre_upgrade`/`post_upgrade` at
+//! all; only the small bookkeeping fields left on `TokenState` need that.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _, StableBTreeMap, StableLog, Storable};
+
+use crate::{Account, Transaction};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const ACCOUNTS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const TX_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(1);
+const TX_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(2);
+const HEAP_SNAPSHOT_MEMORY_ID: MemoryId = MemoryId::new(3);
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// Wraps `Account` for candid-based (de)serialization into stable memory.
+impl Storable for Account {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode Account"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode Account")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Transaction {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode Transaction"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode Transaction")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static ACCOUNTS: RefCell<StableBTreeMap<String, Account, Memory>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(ACCOUNTS_MEMORY_ID)))
+    });
+
+    static TRANSACTIONS: RefCell<StableLog<Transaction, Memory, Memory>> = MEMORY_MANAGER.with(|mm| {
+        RefCell::new(
+            StableLog::init(
+                mm.borrow().get(TX_LOG_INDEX_MEMORY_ID),
+                mm.borrow().get(TX_LOG_DATA_MEMORY_ID),
+            )
+            .expect("failed to initialize the stable transaction log"),
+        )
+    });
+
+    /// Heap-side index from account name to the offsets of transactions
+    /// that touch it, rebuilt from the stable log at boot so lookups never
+    /// have to scan the whole log.
+    static TX_INDEX: RefCell<HashMap<String, Vec<u64>>> = RefCell::new(HashMap::new());
+}
+
+pub fn heap_snapshot_memory() -> Memory {
+    MEMORY_MANAGER.with(|mm| mm.borrow().get(HEAP_SNAPSHOT_MEMORY_ID))
+}
+
+/// Write a length-prefixed blob into the heap-snapshot region, growing it
+/// as needed. Used by `pre_upgrade` to carry over the small `TokenState`
+/// fields that aren't already backed by stable structures.
+pub fn save_heap_snapshot(bytes: &[u8]) {
+    let memory = heap_snapshot_memory();
+    let needed_bytes = 4 + bytes.len() as u64;
+    let needed_pages = (needed_bytes + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+    let current_pages = memory.size();
+    if needed_pages > current_pages {
+        memory.grow(needed_pages - current_pages);
+    }
+    memory.write(0, &(bytes.len() as u32).to_le_bytes());
+    memory.write(4, bytes);
+}
+
+/// Read back the blob written by `save_heap_snapshot`, or an empty vec if
+/// nothing has been saved yet (e.g. a fresh install).
+pub fn load_heap_snapshot() -> Vec<u8> {
+    let memory = heap_snapshot_memory();
+    if memory.size() == 0 {
+        return Vec::new();
+    }
+    let mut len_bytes = [0u8; 4];
+    memory.read(0, &mut len_bytes);
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    let mut bytes = vec![0u8; len];
+    memory.read(4, &mut bytes);
+    bytes
+}
+
+pub fn get_account(name: &str) -> Option<Account> {
+    ACCOUNTS.with(|accounts| accounts.borrow().get(&name.to_string()))
+}
+
+pub fn contains_account(name: &str) -> bool {
+    ACCOUNTS.with(|accounts| accounts.borrow().contains_key(&name.to_string()))
+}
+
+pub fn insert_account(name: String, account: Account) {
+    ACCOUNTS.with(|accounts| accounts.borrow_mut().insert(name, account));
+}
+
+pub fn remove_account(name: &str) {
+    ACCOUNTS.with(|accounts| accounts.borrow_mut().remove(&name.to_string()));
+}
+
+/// Append a transaction to the stable log and update the in-memory index
+/// for both parties, returning the log offset it was written at.
+pub fn append_transaction(tx: Transaction) -> u64 {
+    let from = tx.from.clone();
+    let to = tx.to.clone();
+
+    let offset = TRANSACTIONS.with(|log| {
+        log.borrow_mut().append(&tx).expect("failed to append to the stable transaction log")
+    });
+
+    TX_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        index.entry(from.clone()).or_default().push(offset);
+        if to != from {
+            index.entry(to).or_default().push(offset);
+        }
+    });
+
+    offset
+}
+
+/// All transactions touching `name`, looked up through the in-memory index
+/// rather than scanning the stable log.
+pub fn transactions_for(name: &str) -> Vec<Transaction> {
+    let offsets = TX_INDEX.with(|index| index.borrow().get(name).cloned().unwrap_or_default());
+
+    TRANSACTIONS.with(|log| {
+        let log = log.borrow();
+        offsets.iter().filter_map(|offset| log.get(*offset)).collect()
+    })
+}
+
+/// Rebuild the in-memory transaction index from the stable log. Called on
+/// `init` and `post_upgrade` since the index itself lives on the heap.
+pub fn rebuild_index() {
+    TX_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        index.clear();
+
+        TRANSACTIONS.with(|log| {
+            let log = log.borrow();
+            for offset in 0..log.len() {
+                if let Some(tx) = log.get(offset) {
+                    index.entry(tx.from.clone()).or_default().push(offset);
+                    if tx.to != tx.from {
+                        index.entry(tx.to.clone()).or_default().push(offset);
+                    }
+                }
+            }
+        });
+    });
+}